@@ -1,22 +1,65 @@
 //! Establishes information regarding agents, which incl. both
 //! sending and recieving partners
 
+use super::beacon;
 use super::error::*;
+use super::fragment::{self, Reassembler};
+use super::peers::{self, PeerList};
+use super::session;
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use bincode;
 use serde::{Serialize, Deserialize};
 
 use rand::rngs::OsRng;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use std::net::{UdpSocket, SocketAddrV4};
-//use rsa::{RsaPublicKey, RsaPrivateKey};
-use rsa::{PublicKey, RsaPrivateKey, RsaPublicKey, PaddingScheme};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use sha2::{Digest, Sha256};
+
+/// Size in bytes of the ChaCha20-Poly1305 nonce prefixed to each ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// The practical ceiling on a message body: bounded by how much fragmentation
+/// is willing to carry, rather than by RSA's modulus size (the session key
+/// below is never itself RSA-wrapped, so that limit never applied here).
+const MAX_MESSAGE_LEN: usize = 65000;
+
+/// Leading byte of a data frame, distinguishing it from an in-band
+/// [`session::FRAME_HANDSHAKE`] frame sharing the same socket.
+const FRAME_DATA: u8 = 0;
+
+/// How many datagrams [`Agent::recv_message`] will read while waiting for a
+/// complete data frame before giving up. A rekey datagram or a fragment
+/// belonging to a message that's never completed doesn't advance the
+/// caller's wait on its own, so without a cap a peer that keeps sending
+/// either could block the call forever.
+const MAX_RECEIVE_DATAGRAMS: usize = 4096;
+
+/// Restore `$socket`'s original read/write timeouts and return `Err($err)`
+/// from the enclosing function. Shared by `handshake()` and `listen()` so
+/// every early return during their tightened-timeout window leaves the
+/// socket the way it found it, instead of leaking the tightened handshake
+/// timeout (1 second, or `listen`'s `wait`) onto it forever. `with_handshake_timeout` covers the narrower
+/// case (a rekey mid-conversation) where the whole body fits in one closure.
+macro_rules! fail_restoring_timeouts {
+    ($socket:expr, $old_read:expr, $old_write:expr, $err:expr) => {{
+        $socket.set_read_timeout($old_read).unwrap();
+        $socket.set_write_timeout($old_write).unwrap();
+        return Err($err);
+    }};
+}
 
 /// A description for a given agent, including its name and address
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AgentDescription {
-    addr: Option<SocketAddrV4>,
+    pub(crate) addr: Option<SocketAddrV4>,
     key: RsaPublicKey,
     pub name: String,
 }
@@ -67,14 +110,15 @@ impl AgentDescription {
         return serialized;
     }
 
-    /// Deserialize a bincode vector into an AgentDescription Object
-    ///
-    /// TODO actually verify what we get is an AgentDescription
+    /// Deserialize a bincode vector into an AgentDescription Object. `v` may
+    /// come straight off the wire from an unauthenticated `recv_from`, so a
+    /// malformed buffer is reported as an error rather than unwrapped.
     ///
     /// # Returns
-    /// `AgentDescription`: the deserialized object
-    pub fn deserialize(v:&[u8]) -> Self {
-        bincode::deserialize(v).unwrap()
+    /// `Result<Self, MitteError>`: the deserialized object, or a failure
+    pub fn deserialize(v:&[u8]) -> Result<Self, MitteError> {
+        bincode::deserialize(v)
+            .map_err(|_| MitteError::DescriptionFormatError(String::from("cannot parse agent description")))
     }
 }
 
@@ -88,18 +132,28 @@ impl PartialEq for AgentDescription {
 
 impl Eq for AgentDescription {}
 
-// Don't quite know, but the initializer has to
-// be a function
-fn noneifier() -> Option<UdpSocket> { None }
+/// Governs whether [`Agent::listen`] accepts any peer that completes the
+/// handshake dance, or only peers whose key was explicitly trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustMode {
+    /// Accept any peer, regardless of key, as the original implementation did.
+    Open,
+    /// Only accept peers whose key is in `Agent`'s trusted set.
+    Explicit,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub struct Agent {
     pub profile: AgentDescription,
-    peers: Vec<AgentDescription>,
+    peers: PeerList,
     secret: RsaPrivateKey,
-
-    #[serde(skip, default="noneifier")] 
     socket: Option<UdpSocket>,
+
+    trust_mode: TrustMode,
+    trusted_keys: Vec<RsaPublicKey>,
+
+    reassembler: Reassembler,
+    rekey_policy: session::RekeyPolicy,
 }
 
 impl Agent {
@@ -113,14 +167,121 @@ impl Agent {
         let socket = UdpSocket::bind(profile.addr.expect("fatal: agent-created desc. does not have address"));
         match socket {
             Ok(s) => Ok(Agent { profile,
-                                peers: vec![],
-                                socket:Some(s),
-                                secret:priv_key}),
+                                peers: PeerList::new(),
+                                socket: Some(s),
+                                secret: priv_key,
+                                trust_mode: TrustMode::Open,
+                                trusted_keys: vec![],
+                                reassembler: Reassembler::new(),
+                                rekey_policy: session::RekeyPolicy::default()}),
 
             Err(_) => Err(MitteError::AgentCreationError(String::from("cannot bind to socket")))
         }
     }
 
+    /// Creates an agent whose identity keypair is deterministically derived
+    /// from `secret` rather than generated at random: every node configured
+    /// with the same secret produces, and therefore trusts, the same
+    /// identity. This is a zero-configuration mode for a small private
+    /// group who can share a secret out-of-band but don't want to manage a
+    /// trusted-key list by hand. The agent comes back in
+    /// [`TrustMode::Explicit`] with its own (shared) key already trusted.
+    ///
+    /// # Arguments
+    /// - `addr:&str`: the IPv4 socket address to bind to
+    /// - `name:&str`: the agent's name. Must be <= 20 chars
+    /// - `secret:&str`: the shared secret every node in the group is configured with
+    ///
+    /// # Returns
+    /// `Result<Self, MitteError>`: potentially an instance of `Agent`
+    pub fn from_shared_secret(addr: &str, name: &str, secret: &str) -> Result<Self, MitteError> {
+        let seed: [u8; 32] = Sha256::digest(secret.as_bytes()).into();
+        let mut rng = ChaCha20Rng::from_seed(seed);
+
+        let priv_key = if let Ok(k) = RsaPrivateKey::new(&mut rng, 2048) { k }
+        else {return Err(MitteError::AgentCreationError(String::from("cannot create key")))};
+
+        let pub_key = RsaPublicKey::from(&priv_key);
+        let pub_key_serialized = bincode::serialize(&pub_key).unwrap();
+        let profile = AgentDescription::new(addr, name, &pub_key_serialized)?;
+
+        let socket = UdpSocket::bind(profile.addr.expect("fatal: agent-created desc. does not have address"));
+        match socket {
+            Ok(s) => Ok(Agent { profile,
+                                peers: PeerList::new(),
+                                socket: Some(s),
+                                secret: priv_key,
+                                trust_mode: TrustMode::Explicit,
+                                trusted_keys: vec![pub_key],
+                                reassembler: Reassembler::new(),
+                                rekey_policy: session::RekeyPolicy::default()}),
+
+            Err(_) => Err(MitteError::AgentCreationError(String::from("cannot bind to socket")))
+        }
+    }
+
+    /// Set how strict [`Agent::listen`] is about which peers it accepts.
+    pub fn set_trust_mode(&mut self, mode: TrustMode) {
+        self.trust_mode = mode;
+    }
+
+    /// Add a key to the trusted set consulted when [`TrustMode::Explicit`]
+    /// is active.
+    pub fn trust_key(&mut self, key: RsaPublicKey) {
+        if !self.trusted_keys.contains(&key) {
+            self.trusted_keys.push(key);
+        }
+    }
+
+    /// Configure how often a session this agent negotiates should be
+    /// renegotiated. Applies to handshakes run after this call; a session
+    /// already established keeps whatever policy it was negotiated under.
+    ///
+    /// `policy` is clamped to a sane floor first, so a degenerate value
+    /// (e.g. `max_messages: 0`) can't turn every `send_message` into a
+    /// full rekey round-trip.
+    pub fn set_rekey_policy(&mut self, policy: session::RekeyPolicy) {
+        self.rekey_policy = policy.clamped();
+    }
+
+    /// Whether `key` would be accepted by the current trust policy: always,
+    /// in [`TrustMode::Open`]; only if it's in the trusted set, in
+    /// [`TrustMode::Explicit`].
+    pub fn is_trusted(&self, key: &RsaPublicKey) -> bool {
+        match self.trust_mode {
+            TrustMode::Open => true,
+            TrustMode::Explicit => self.trusted_keys.contains(key),
+        }
+    }
+
+    /// Run `f` with `socket`'s read/write timeouts tightened to one second
+    /// for the duration of the call, restoring whatever was set before
+    /// regardless of whether `f` succeeds. `handshake()` applies this same
+    /// tightening (and `listen()` its own `wait`) around the identity
+    /// handshake so a dropped
+    /// datagram fails one call instead of blocking forever; a rekey run
+    /// mid-conversation -- whether `send_message` initiating one or
+    /// `recv_message` completing one requested in-band -- shares that same
+    /// lossy-UDP risk and needs the same bound.
+    fn with_handshake_timeout<T>(
+        socket: &UdpSocket,
+        f: impl FnOnce() -> Result<T, MitteError>,
+    ) -> Result<T, MitteError> {
+        let second = Duration::new(1, 0);
+        let old_read_timeout = socket.read_timeout().unwrap();
+        let old_write_timeout = socket.write_timeout().unwrap();
+
+        socket.set_read_timeout(Some(second)).unwrap();
+        socket.set_write_timeout(Some(second)).unwrap();
+
+        let result = f();
+
+        socket.set_read_timeout(old_read_timeout).unwrap();
+        socket.set_write_timeout(old_write_timeout).unwrap();
+
+        result
+    }
+
     /// Automatically bind to the descripted UDP socket if not bound, otherwise do nothing
     ///
     /// # Returns
@@ -140,10 +301,34 @@ impl Agent {
         } else { Ok(()) }
     }
 
+    /// Encode this agent's address into a time-rotating beacon token that a
+    /// peer holding the same `shared_key` can turn back into an address with
+    /// [`beacon::parse`], without either side needing a registry to look
+    /// each other up in.
+    ///
+    /// # Returns
+    /// `Result<String, MitteError>`: the beacon token, or a failure if this
+    /// agent has no address to encode
+    pub fn beacon(&self, shared_key: &[u8]) -> Result<String, MitteError> {
+        match self.profile.addr {
+            Some(addr) => Ok(beacon::encode(&addr, shared_key)),
+            None => Err(MitteError::AgentCreationError(String::from("agent has no address"))),
+        }
+    }
+
     pub fn handshake(&mut self, target: &AgentDescription) -> Result<(), MitteError> {
         // The handshake subrutine is a very long subroutine therefore, we shall attempt to
         // illustrate parts of it.
 
+        // In explicit trust mode, consult the trust policy before we ever
+        // talk to `target` -- `listen()` already rejects an untrusted peer
+        // on the accepting side, but the initiating side was never checked,
+        // letting this agent freely initiate against and establish a
+        // session with an arbitrary untrusted key.
+        if self.trust_mode == TrustMode::Explicit && !self.is_trusted(&target.key) {
+            return Err(MitteError::HandshakeError(String::from("peer key is not trusted")));
+        }
+
         // We begin by either getting or rebinding the socket if the socket was
         // no longer bound
         self.autobind()?;
@@ -160,30 +345,47 @@ impl Agent {
             socket.set_read_timeout(Some(second)).unwrap();
             socket.set_write_timeout(Some(second)).unwrap();
 
+            // Every early return below must restore the original timeouts
+            // first -- otherwise a handshake that fails partway (a dropped
+            // datagram, a rejected confirm) would leave the socket clamped
+            // to this 1-second timeout forever, silently changing the
+            // blocking behavior of every later call on it.
+
             // We first attempt to connect to our target peer
             match socket.connect(target.addr.unwrap()) {
                 Ok(_) => (),
-                Err(_) => { return Err(MitteError::HandshakeError(String::from("peer disconnected"))); }
+                Err(_) => fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::HandshakeError(String::from("peer disconnected"))
+                ),
             }
 
             // We then send our mating message inviting to bind, telling nothing about ourselves
             // it looks very simple: 0 0 0 0 0 0 0, just 8 zeros
-            socket.send(&[0;8]).unwrap(); 
+            socket.send(&[0;8]).unwrap();
 
             // We now hope that we get an acknowledge message back, that would be good so we could
             // introduce ourselves. The ack mesage is eight eights: 8 8 8 8 8 8 8 8
             let mut buf = [0;8]; // initialize a buffer of 8 zeros
-            socket.recv(&mut buf).unwrap();
+            if socket.recv(&mut buf).is_err() {
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::HandshakeError(String::from("peer disconnected"))
+                );
+            }
 
             // Check whether or not we actually got eight eights back
             if buf != [8;8] {
-                return Err(MitteError::HandshakeError(String::from("handshake unacknowledged")));
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::HandshakeError(String::from("handshake unacknowledged"))
+                );
             }
 
             // Ok, its time to tell our peer a little bit about ourselves
             // i.e. send them our agent description
             let desc = self.profile.serialize();
-            socket.send(&desc).unwrap(); 
+            socket.send(&desc).unwrap();
 
             // We now try to recieve four things, which has the shape of
             // 1 x y 1. This is the reciept acknowledgement. x, y are encoded
@@ -191,40 +393,57 @@ impl Agent {
             //
             // 1. x - 1 (accept) 0 (reject)
             // 2. y - 1 (new connection) 0 (previous connection)
-            let mut buf = [0;4]; // initialize a buffer of 4 zeros 
-            socket.recv(&mut buf).unwrap();
+            let mut buf = [0;4]; // initialize a buffer of 4 zeros
+            if socket.recv(&mut buf).is_err() {
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::HandshakeError(String::from("peer disconnected"))
+                );
+            }
 
             // We first check that the ack package is correctly 1-padded
             if !(buf[0] == buf[3] && buf[3] == 1) {
-                return Err(MitteError::HandshakeError(String::from("handshake unacknowledged")));
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::HandshakeError(String::from("handshake unacknowledged"))
+                );
             }
 
-            // We then check that the ack has not been rejected 
+            // We then check that the ack has not been rejected
             if buf[1] == 0 {
-                return Err(MitteError::HandshakeError(String::from("handshake rejected")));
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::HandshakeError(String::from("handshake rejected"))
+                );
             }
 
             // We then check whether it is a new connection
             // if so, we ensure that we have not seen the peer before + add them
             // if not, we ensure that we have + update them
-            if buf[2] == 1 && !self.peers.contains(target) {
+            let already_known = self.peers.contains(target);
+            if buf[2] == 1 && !already_known {
                 // new connection
-                self.peers.push(target.clone());
-            } else if buf[2] == 0 && self.peers.contains(target) {
-                // these next two lines may seem real silly, but
-                // the point is that PartialEq on `AgentDescription`
-                // is defined such that there is actually
-                let mut vec_filtered = self.peers.clone()
-                    .into_iter()
-                    .filter(|v| v != target)
-                    .collect::<Vec<AgentDescription>>();
-                vec_filtered.push(target.clone());
-                self.peers = vec_filtered;
+                self.peers.upsert(target.clone(), peers::DEFAULT_TIMEOUT);
+            } else if buf[2] == 0 && already_known {
+                self.peers.upsert(target.clone(), peers::DEFAULT_TIMEOUT);
             } else {
                 // return an error if they claim we've met before but we've not
-                return Err(MitteError::HandshakeError(String::from("handshake connection malformed")));
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::HandshakeError(String::from("handshake connection malformed"))
+                );
             }
 
+            // With the identity handshake done, run the ephemeral DH
+            // exchange (e, <-e,ee, confirm) to establish a forward-secret
+            // session for this peer. This runs under the same tightened
+            // timeouts as the rest of the handshake.
+            let new_session = match session::run_initiator(socket, &self.secret, &target.key, self.rekey_policy.clone()) {
+                Ok(session) => session,
+                Err(err) => fail_restoring_timeouts!(socket, old_read_timeout, old_write_timeout, err),
+            };
+            self.peers.set_session(&target.name, new_session);
+
             // We now set the original timeouts back
             socket.set_read_timeout(old_read_timeout).unwrap();
             socket.set_write_timeout(old_write_timeout).unwrap();
@@ -249,50 +468,94 @@ impl Agent {
             socket.set_read_timeout(Some(second)).unwrap();
             socket.set_write_timeout(Some(second)).unwrap();
 
+            // Every early return below must restore the original timeouts
+            // first -- see the matching comment in `handshake()`.
+
             // We first by waiting to recieve a buffer of 8 zeros to align
             let mut buf = [1;8]; // initialize a buffer of 8 zeros
-            let (_, sender) = socket.recv_from(&mut buf).unwrap();
-
-            // If we didn't get 8 zeros, give up. 
+            let (_, sender) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(_) => fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::ListenError(String::from("no peer connected in time"))
+                ),
+            };
+
+            // If we didn't get 8 zeros, give up.
             if buf != [0;8] {
-                return Err(MitteError::ListenError(String::from("malformed input")));
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::ListenError(String::from("malformed input"))
+                );
             }
 
-            // We send to our original sender the ack message and continue 
+            // We send to our original sender the ack message and continue
             // to wait for their full description of themselves
             socket.send_to(&[8;8], sender).unwrap();
-            
+
             // And now, we wait for the reciept of the description of our peer
             let mut peer_desc = [0;320];
-            socket.recv_from(&mut peer_desc).unwrap();
-            let peer = AgentDescription::deserialize(&peer_desc);
+            if socket.recv_from(&mut peer_desc).is_err() {
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::ListenError(String::from("peer disconnected"))
+                );
+            }
+            // Trust mode is checked further below, but this parse has to
+            // succeed first -- a spoofed or malformed description must fail
+            // the handshake here rather than panic before that check, and
+            // before the peer's key is ever known, ever runs.
+            let peer = match AgentDescription::deserialize(&peer_desc) {
+                Ok(p) => p,
+                Err(_) => fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::ListenError(String::from("peer description malformed"))
+                ),
+            };
 
             // Make sure that our peer actually sent an address
             if let None = peer.addr {
-                return Err(MitteError::ListenError(String::from("peer did not send address")));
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::ListenError(String::from("peer did not send address"))
+                );
             }
 
             // Check whether or not we have the peer in the peers list
-            // if we do, swap out the peer with the one that we got
-            // so that we could update the address if needed (i.e. if we
-            // have a peer 
-            let mut is_new = 1;
-            if self.peers.contains(&peer) {
-                is_new = 0;
-                let mut vec_filtered = self.peers.clone()
-                    .into_iter()
-                    .filter(|v| v != &peer)
-                    .collect::<Vec<AgentDescription>>();
-                vec_filtered.push(peer.clone());
-                self.peers = vec_filtered;
-            } else {
-                self.peers.push(peer.clone())
+            // if we do, update their entry (this is also how we pick up an
+            // address change, via `PeerList::upsert`) instead of the old
+            // clone-filter-rebuild over the whole list
+            let is_new = if self.peers.contains(&peer) { 0 } else { 1 };
+
+            // In explicit trust mode, a peer whose key isn't one we trust
+            // doesn't get to complete the handshake at all: we send the
+            // reject ack and bail before touching the peer table.
+            if self.trust_mode == TrustMode::Explicit && !self.is_trusted(&peer.key) {
+                let buf = [1, 0, is_new, 1];
+                socket.send_to(&buf, sender).unwrap();
+                fail_restoring_timeouts!(
+                    socket, old_read_timeout, old_write_timeout,
+                    MitteError::ListenError(String::from("peer key is not trusted"))
+                );
             }
 
+            self.peers.upsert(peer.clone(), peers::DEFAULT_TIMEOUT);
+
             // We finally acknowledge the final sent message and be done
             let buf = [1, 1, is_new, 1]; // initialize a buffer of 4 zeros
             socket.send_to(&buf, sender).unwrap();
 
+            // Connect to the sender so the remaining handshake traffic
+            // (the ephemeral DH exchange) can use the usual send/recv pair,
+            // then run the responder side of it to derive a forward-secret
+            // session for this peer.
+            socket.connect(sender).unwrap();
+            let new_session = match session::run_responder(socket, &self.secret, &peer.key, self.rekey_policy.clone()) {
+                Ok(session) => session,
+                Err(err) => fail_restoring_timeouts!(socket, old_read_timeout, old_write_timeout, err),
+            };
+            self.peers.set_session(&peer.name, new_session);
+
             // We now set the original timeouts back
             socket.set_read_timeout(old_read_timeout).unwrap();
             socket.set_write_timeout(old_write_timeout).unwrap();
@@ -305,7 +568,43 @@ impl Agent {
 
     }
 
-    /// Sends a message to a target peer. 
+    /// Evict any peer that hasn't been heard from within its liveness
+    /// timeout, returning the descriptions of whoever got evicted.
+    ///
+    /// # Returns
+    /// `Vec<AgentDescription>`: the peers that were dropped
+    pub fn maintenance(&mut self) -> Vec<AgentDescription> {
+        self.peers.maintenance()
+    }
+
+    /// Every peer we consider live right now, along with when it was last
+    /// heard from.
+    ///
+    /// # Returns
+    /// `Vec<(AgentDescription, Instant)>`: each live peer and its last-seen time
+    pub fn live_peers(&self) -> Vec<(AgentDescription, Instant)> {
+        self.peers.live_peers()
+    }
+
+    /// Re-handshake every peer whose address changed since we last talked
+    /// to them and whose reconnect backoff has elapsed. A peer that keeps
+    /// failing to reconnect backs off exponentially, capped at an hour,
+    /// rather than being retried every call.
+    ///
+    /// # Returns
+    /// `Result<(), MitteError>`: always `Ok(())`; a failed re-handshake is
+    /// recorded against that peer's backoff and is not reported to the caller
+    pub fn reconnect(&mut self) -> Result<(), MitteError> {
+        for desc in self.peers.due_for_reconnect() {
+            match self.handshake(&desc) {
+                Ok(()) => self.peers.record_reconnect_attempt(&desc.name, true),
+                Err(_) => self.peers.record_reconnect_attempt(&desc.name, false),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a message to a target peer.
     ///
     /// # Arguments
     /// - `msg:&[u8]`: the message you want to send, in the form of an arr of u8s
@@ -321,47 +620,94 @@ impl Agent {
         // We then check that our UDP port is bound
         self.autobind()?;
 
-        // If the message length is larger than 512 units, we consider it too long
-        if msg.len() > 512 {
+        // The old 512-byte cap came from RSA's own modulus limit, which no
+        // longer applies now that the message body is carried under
+        // ChaCha20-Poly1305 rather than raw RSA. The frame is split across
+        // as many datagrams as it needs, so what's left is `MAX_MESSAGE_LEN`.
+        if msg.len() > MAX_MESSAGE_LEN {
             return Err(MitteError::SendError(String::from("message too long")));
         }
 
-        // We then match the correct peer to communicate with
-        if let Some(peer) = self.peers.iter().filter(|r| r.name == peer_name).next() {
+        // We then match the correct peer to communicate with. We work off
+        // a clone of their description rather than holding a borrow into
+        // `self.peers`, since we need `&mut self.peers` again below to
+        // check and refresh the session.
+        let peer = match self.peers.get(peer_name) {
+            Some(desc) => desc.clone(),
+            None => return Err(MitteError::SendError(String::from("name is not in peers list"))),
+        };
 
+        {
             // We also make sure that the socket is bound
             if let Some(socket) = &self.socket {
 
                 // If connection with the peer was not successful, we error
                 if let Err(_) = socket.connect(peer.addr.unwrap()) {
-                    return Err(MitteError::SendError(String::from("peer disconnected"))); 
+                    return Err(MitteError::SendError(String::from("peer disconnected")));
                 }
 
-                // We then encode the data as needed
-                let padding = PaddingScheme::new_pkcs1v15_encrypt();
-                let enc_data:Vec<u8> = peer.key.encrypt(&mut rng, padding, msg).unwrap();
-
-                // Finally, we add establishment values 0 0 + length of the communication
-                // this implementation of UDP only sends `u8`s, so we split the length up
-                // into two u8s
-                let data_len = enc_data.len() as u16;
-                let (a,b) = ((data_len >> 8) as u8, data_len as u8);
-
-                // We chunck the start digits + the bitshifted leng along
-                let chained_data = [0,0,a,b] 
+                // If our session with this peer is due for a rekey (or we
+                // never finished negotiating one), transparently renegotiate
+                // it now rather than forcing the caller to notice and
+                // re-handshake by hand.
+                let needs_rekey = match self.peers.session(peer_name) {
+                    Some(s) => s.needs_rekey(),
+                    None => true,
+                };
+                if needs_rekey {
+                    let new_session = Agent::with_handshake_timeout(socket, || {
+                        session::run_initiator(socket, &self.secret, &peer.key, self.rekey_policy.clone())
+                    })?;
+                    self.peers.set_session(peer_name, new_session);
+                }
+                self.peers.note_message_sent(peer_name);
+                self.peers.touch(peer_name);
+
+                // The session negotiated above (fresh or already live) is
+                // what actually protects the payload: its send key is
+                // forward-secret, since it only ever existed in memory and
+                // is never itself carried under the peer's long-lived RSA
+                // key the way the old per-message content key was.
+                let send_key = match self.peers.session(peer_name) {
+                    Some(s) => *s.send_key(),
+                    None => {
+                        return Err(MitteError::SendError(String::from("no session negotiated")));
+                    }
+                };
+
+                let mut nonce_bytes = [0u8; NONCE_LEN];
+                rng.fill_bytes(&mut nonce_bytes);
+
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&send_key));
+                let ciphertext = cipher
+                    .encrypt(Nonce::from_slice(&nonce_bytes), msg)
+                    .map_err(|_| MitteError::SendError(String::from("could not encrypt message")))?;
+
+                // Frame as `[FRAME_DATA, <nonce>, <ciphertext || tag>]`.
+                let chained_data = [FRAME_DATA]
                     .iter()
-                    .chain(enc_data.iter())
+                    .chain(nonce_bytes.iter())
+                    .chain(ciphertext.iter())
                     .cloned()
                     .collect::<Vec<u8>>();
 
-                // Send it along!
-                socket.send(&chained_data).unwrap();
-                return Ok(());
+                // The frame above may not fit in a single UDP datagram (or
+                // may simply be large enough that we'd rather not risk IP
+                // fragmentation), so split it into fragments and send each
+                // as its own datagram; `recv_message` reassembles them on
+                // the other end.
+                // The message id's top byte is each fragment's leading byte on
+                // the wire, so pin it to `FRAME_DATA`: `recv_message` would
+                // otherwise take any fragment whose id happened to start
+                // with `FRAME_HANDSHAKE` for a rekey request and drop it.
+                let message_id = (rng.next_u32() & 0x00ff_ffff) | ((FRAME_DATA as u32) << 24);
+                for frag in fragment::fragment(message_id, &chained_data) {
+                    socket.send(&frag).unwrap();
+                }
+                Ok(())
             } else {
-                return Err(MitteError::SendError(String::from("socket unbound")));
+                Err(MitteError::SendError(String::from("socket unbound")))
             }
-        } else {
-            return Err(MitteError::SendError(String::from("name is not in peers list"))); 
         }
     }
 
@@ -374,28 +720,110 @@ impl Agent {
         self.autobind()?;
 
         if let Some(socket) = &self.socket {
-            // We first recieve a message
-            let mut buf = [0;1024]; // TODO: len checks!
-            socket.recv(&mut buf).unwrap();
+            // The peer this socket is connected to right now is the only
+            // one we can hear from; used both to dispatch an in-band rekey
+            // to the right session and, once we have an actual data frame,
+            // to decrypt it under that peer's session key.
+            let peer_addr = match socket.peer_addr() {
+                Ok(std::net::SocketAddr::V4(v4)) => v4,
+                _ => return Err(MitteError::ReceiveError(String::from("socket not connected"))),
+            };
+
+            // A message may have been split across several datagrams by
+            // `send_message`, and our peer may interleave an in-band rekey
+            // handshake before its next data frame; keep reading, servicing
+            // any rekey in-line, until a full data frame comes back out.
+            // Capped at `MAX_RECEIVE_DATAGRAMS` so a peer that never
+            // completes a message (or just floods unrelated noise) fails
+            // the call instead of blocking it forever.
+            let mut frame = None;
+            for _ in 0..MAX_RECEIVE_DATAGRAMS {
+                let mut buf = [0; fragment::MAX_FRAGMENT_LEN];
+                let n = match socket.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(_) => return Err(MitteError::ReceiveError(String::from("peer disconnected"))),
+                };
+                let datagram = &buf[..n];
+
+                if datagram.first() == Some(&session::FRAME_HANDSHAKE) {
+                    // A stray or duplicate handshake datagram here isn't
+                    // necessarily an attack: `run_initiator`'s own resend of
+                    // `e` when a response is late can produce one under
+                    // ordinary jitter. If completing it fails -- the frame
+                    // doesn't parse, or `complete_responder`'s wait for
+                    // `Confirm` times out or reads something that isn't one
+                    // -- that's a rejected rekey attempt, not a failure of
+                    // the `recv_message` call the caller is waiting on, so
+                    // drop it and keep looping for the data frame instead of
+                    // propagating the error out of this function.
+                    let init = match session::parse_handshake_frame(datagram) {
+                        Ok(init) => init,
+                        Err(_) => continue,
+                    };
+                    let peer = match self.peers.attribute(&peer_addr) {
+                        Some(desc) => desc.clone(),
+                        None => continue, // rekey request from an unknown peer; ignore it
+                    };
+                    let completed = Agent::with_handshake_timeout(socket, || {
+                        session::complete_responder(socket, &self.secret, &peer.key, init, self.rekey_policy.clone())
+                    });
+                    if let Ok(new_session) = completed {
+                        self.peers.set_session(&peer.name, new_session);
+                        self.peers.touch(&peer.name);
+                    }
+                    continue;
+                }
 
-            // We then check that the setup values are correct
-            if buf[0] != buf[1] || buf[1] != 0 {
-                return Err(MitteError::ReceiveError(String::from("incorrect setup values")));
+                if let Some(f) = self.reassembler.insert(datagram) {
+                    frame = Some(f);
+                    break;
+                }
             }
+            let frame = match frame {
+                Some(f) => f,
+                None => {
+                    return Err(MitteError::ReceiveError(String::from(
+                        "gave up waiting for a complete message",
+                    )));
+                }
+            };
 
-            // We then get the appropriate length for our data by bitshifting
-            // it back (i.e. constructing a `u16` out of two `u8` because
-            // UDP can't send `u16`s
+            if frame.first() != Some(&FRAME_DATA) {
+                return Err(MitteError::ReceiveError(String::from("incorrect setup values")));
+            }
 
-            let len = ((buf[2] as u16) << 8 + buf[3]) as usize;
+            if frame.len() < 1 + NONCE_LEN {
+                return Err(MitteError::ReceiveError(String::from("incorrect setup values")));
+            }
 
-            // We use typical decoding schemes to decode it
-            let padding = PaddingScheme::new_pkcs1v15_encrypt();
-            match self.secret.decrypt(padding, &buf[4..len+4]) {
-                Ok(d) => { Ok(d) },
-                Err(_) => {
-                    return Err(MitteError::ReceiveError(String::from("decryption error")));
-                }
+            let nonce_bytes = &frame[1..1 + NONCE_LEN];
+            let ciphertext = &frame[1 + NONCE_LEN..];
+
+            let peer = match self.peers.attribute(&peer_addr) {
+                Some(desc) => desc.clone(),
+                None => return Err(MitteError::ReceiveError(String::from("message from unknown peer"))),
+            };
+            let recv_key = match self.peers.session(&peer.name) {
+                Some(s) => *s.recv_key(),
+                None => return Err(MitteError::ReceiveError(String::from("no session negotiated"))),
+            };
+            self.peers.touch(&peer.name);
+
+            // `recv_key` is a `[u8; 32]` owned by our own `Session`, not
+            // bytes an attacker chose the length of (the RSA-wrapped
+            // per-message key this used to unwrap, and panic on if it
+            // decrypted to the wrong length, is gone now that the session
+            // key itself is what encrypts the payload), so `from_slice`
+            // here can't fail on a hostile peer the way it used to.
+            //
+            // Authenticate and decrypt the body under the session's recv
+            // key; a flipped ciphertext byte or a key that doesn't match
+            // fails the Poly1305 tag check here rather than silently
+            // producing garbage.
+            let cipher = ChaCha20Poly1305::new(Key::from_slice(&recv_key));
+            match cipher.decrypt(Nonce::from_slice(nonce_bytes), ciphertext) {
+                Ok(d) => Ok(d),
+                Err(_) => Err(MitteError::ReceiveError(String::from("message authentication failed"))),
             }
 
         } else {
@@ -404,9 +832,143 @@ impl Agent {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::thread;
+
+    /// Binds an [`Agent`] to an OS-assigned loopback port and patches its
+    /// profile to that actual address, since `Agent::new` only knows the
+    /// `:0` it was asked to bind to.
+    fn local_agent(name: &str) -> Agent {
+        let mut agent = Agent::new("127.0.0.1:0", name).unwrap();
+        match agent.socket.as_ref().unwrap().local_addr().unwrap() {
+            SocketAddr::V4(v4) => agent.profile.addr = Some(v4),
+            SocketAddr::V6(_) => unreachable!("bound to an IPv4 loopback address"),
+        }
+        agent
+    }
+
+    #[test]
+    fn handshake_then_send_and_receive_round_trips_a_message_both_ways() {
+        let mut initiator = local_agent("initiator");
+        let mut responder = local_agent("responder");
+        let responder_profile = responder.profile.clone();
+
+        let listener = thread::spawn(move || {
+            responder.listen(5).unwrap();
+            responder
+        });
+
+        initiator.handshake(&responder_profile).unwrap();
+        let mut responder = listener.join().unwrap();
+
+        initiator.send_message(b"hello from initiator", "responder").unwrap();
+        assert_eq!(responder.recv_message().unwrap(), b"hello from initiator");
+
+        responder.send_message(b"hello from responder", "initiator").unwrap();
+        assert_eq!(initiator.recv_message().unwrap(), b"hello from responder");
+    }
+
+    #[test]
+    fn send_message_transparently_rekeys_under_a_low_max_messages_policy() {
+        let mut initiator = local_agent("initiator");
+        let mut responder = local_agent("responder");
+
+        let low_policy = session::RekeyPolicy { max_messages: 2, ..session::RekeyPolicy::default() };
+        initiator.set_rekey_policy(low_policy.clone());
+        responder.set_rekey_policy(low_policy);
+
+        let responder_profile = responder.profile.clone();
+        let listener = thread::spawn(move || {
+            responder.listen(5).unwrap();
+            responder
+        });
+
+        initiator.handshake(&responder_profile).unwrap();
+        let mut responder = listener.join().unwrap();
+
+        // With max_messages clamped to 2, the third send crosses the
+        // threshold and send_message renegotiates the session in-band before
+        // the data frame goes out. The rekey is a synchronous round trip on
+        // the same socket recv_message reads from, so the receiving side
+        // has to be blocked in recv_message concurrently -- not just called
+        // afterwards -- for the responder to answer it.
+        let messages: Vec<String> = (0..5).map(|i| format!("message {i}")).collect();
+        let expected = messages.clone();
+        let receiver = thread::spawn(move || {
+            (0..expected.len()).map(|_| responder.recv_message().unwrap()).collect::<Vec<_>>()
+        });
+
+        for msg in &messages {
+            initiator.send_message(msg.as_bytes(), "responder").unwrap();
+        }
+
+        let received = receiver.join().unwrap();
+        for (msg, got) in messages.iter().zip(received) {
+            assert_eq!(got, msg.as_bytes());
+        }
+    }
 
+    #[test]
+    fn listen_returns_an_error_instead_of_panicking_when_nobody_connects() {
+        // Stands in for a dropped/delayed datagram: nobody ever completes
+        // the handshake dance inside the wait, so `recv_from` returns
+        // `WouldBlock` under the 1-second-per-attempt timeout rather than
+        // ever producing a datagram to deserialize.
+        let mut agent = local_agent("lonely");
+        assert!(agent.listen(1).is_err());
+    }
 
+    #[test]
+    fn handshake_returns_an_error_instead_of_panicking_against_an_unresponsive_peer() {
+        // An address nobody is bound to any more: valid-looking, but every
+        // recv() in the handshake dance times out instead of completing.
+        let dead_addr = {
+            let vacated = local_agent("nobody");
+            vacated.profile.clone()
+        };
+
+        let mut seeker = local_agent("seeker");
+        assert!(seeker.handshake(&dead_addr).is_err());
+    }
+
+    #[test]
+    fn listen_rejects_an_untrusted_peer_in_explicit_trust_mode() {
+        let mut initiator = local_agent("initiator");
+        let mut responder = local_agent("responder");
+        // Nothing is ever added to the trusted set, so the initiator's key
+        // is rejected no matter what it is.
+        responder.set_trust_mode(TrustMode::Explicit);
+
+        let responder_profile = responder.profile.clone();
+        let listener = thread::spawn(move || responder.listen(5));
+
+        // The reject ack (`[1, 0, is_new, 1]`) is what makes handshake() see
+        // this as a rejection rather than a dropped datagram.
+        match initiator.handshake(&responder_profile).unwrap_err() {
+            MitteError::HandshakeError(msg) => assert_eq!(msg, "handshake rejected"),
+            other => panic!("expected a rejected handshake, got {other:?}"),
+        }
 
+        match listener.join().unwrap().unwrap_err() {
+            MitteError::ListenError(msg) => assert_eq!(msg, "peer key is not trusted"),
+            other => panic!("expected listen() to reject the untrusted key, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn from_shared_secret_with_the_same_secret_yields_the_same_identity_key() {
+        let a = Agent::from_shared_secret("127.0.0.1:0", "a", "correct horse battery staple").unwrap();
+        let b = Agent::from_shared_secret("127.0.0.1:0", "b", "correct horse battery staple").unwrap();
+
+        assert_eq!(RsaPublicKey::from(&a.secret), RsaPublicKey::from(&b.secret));
+        assert_eq!(a.trust_mode, TrustMode::Explicit);
+        assert!(a.is_trusted(&RsaPublicKey::from(&a.secret)));
+    }
+}
 
 
 
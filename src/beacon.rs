@@ -0,0 +1,292 @@
+//! Time-rotating address beacons.
+//!
+//! Lets an [`crate::agent::Agent`] fold its [`SocketAddrV4`] into a short,
+//! ASCII-safe token that can be pasted into an out-of-band channel (chat,
+//! email, a sticky note) for a peer to [`parse`] back into an address,
+//! without either side needing a registry to look each other up in. Tokens
+//! are keyed by a shared secret both sides already agree on and rotate
+//! every hour, so a token copied out of an old conversation stops working
+//! on its own.
+//!
+//! This is obscurity, not cryptography: the "keystream" below is a keyed
+//! hash, not an AEAD, and nothing here authenticates who minted a token.
+//! Don't use it as a substitute for the identity handshake in
+//! [`crate::agent`].
+
+use super::error::*;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+
+const ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Marks the framing section a keystream block belongs to, so the decoder
+/// can tell a sentinel byte from the address payload even though both are
+/// produced by the same keyed hash.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum Section {
+    Begin = 0,
+    End = 1,
+    Data = 2,
+    Seed = 3,
+}
+
+/// Total size of an encoded payload: 1 seed byte + 1 begin sentinel +
+/// 4 IPv4 octets + 2 port bytes + 1 end sentinel.
+const PAYLOAD_LEN: usize = 9;
+
+/// The number of one-hour slots since the epoch, truncated to 16 bits so a
+/// shared key's keystream cycles rather than growing without bound. Two
+/// slots (current and previous) are accepted on decode to tolerate clocks
+/// that are out of sync across the hour boundary.
+fn time_slot(now: SystemTime) -> u16 {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    ((secs / 3600) & 0xffff) as u16
+}
+
+/// A single keystream byte for a given framing section, derived from
+/// `SHA512(shared_key || [section, seed, iter] || time_slot)`. `iter`
+/// indexes successive 64-byte hash blocks for sections whose payload is
+/// longer than one block; ours never need more than one.
+///
+/// `Section::Seed` is the one exception to `seed` being the random byte
+/// picked per token: that section hides the seed itself, so it can't be
+/// keyed off a value the decoder doesn't have yet, and is always called
+/// with `seed = 0` instead.
+fn keystream_block(shared_key: &[u8], section: Section, seed: u8, iter: u8, slot: u16) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.update(shared_key);
+    hasher.update([section as u8, seed, iter]);
+    hasher.update(slot.to_be_bytes());
+
+    let mut block = [0u8; 64];
+    block.copy_from_slice(&hasher.finalize());
+    block
+}
+
+fn xor_with_keystream(shared_key: &[u8], section: Section, seed: u8, slot: u16, data: &mut [u8]) {
+    let block = keystream_block(shared_key, section, seed, 0, slot);
+    for (byte, key) in data.iter_mut().zip(block.iter()) {
+        *byte ^= key;
+    }
+}
+
+/// Encode `addr` into a base62 token under `shared_key`, valid for the
+/// current one-hour slot.
+pub fn encode(addr: &SocketAddrV4, shared_key: &[u8]) -> String {
+    let slot = time_slot(SystemTime::now());
+
+    let mut seed = [0u8; 1];
+    OsRng.fill_bytes(&mut seed);
+    let seed = seed[0];
+
+    let mut payload = [0u8; PAYLOAD_LEN];
+
+    // The seed byte itself is hidden under its own keystream section so it
+    // isn't carried in the clear; see the note on `Section::Seed`.
+    let mut seed_byte = [seed];
+    xor_with_keystream(shared_key, Section::Seed, 0, slot, &mut seed_byte);
+    payload[0] = seed_byte[0];
+
+    // A single-byte sentinel before and after the address data so the
+    // decoder can frame the payload and recognize whether it has the right
+    // key/slot before trusting the address it recovers.
+    let mut begin = [0xA5u8];
+    xor_with_keystream(shared_key, Section::Begin, seed, slot, &mut begin);
+    payload[1] = begin[0];
+
+    let mut data = [0u8; 6];
+    data[..4].copy_from_slice(&addr.ip().octets());
+    data[4..].copy_from_slice(&addr.port().to_be_bytes());
+    xor_with_keystream(shared_key, Section::Data, seed, slot, &mut data);
+    payload[2..8].copy_from_slice(&data);
+
+    let mut end = [0x5Au8];
+    xor_with_keystream(shared_key, Section::End, seed, slot, &mut end);
+    payload[8] = end[0];
+
+    base62_encode(&payload)
+}
+
+/// Decode a token produced by [`encode`] back into a [`SocketAddrV4`],
+/// accepting tokens minted in the current or immediately previous hour
+/// slot.
+pub fn parse(token: &str, shared_key: &[u8]) -> Result<SocketAddrV4, MitteError> {
+    let payload = base62_decode(token, PAYLOAD_LEN)?;
+
+    let begin = payload[1];
+    let data: [u8; 6] = payload[2..8].try_into().unwrap();
+    let end = payload[8];
+
+    let now_slot = time_slot(SystemTime::now());
+    for slot in [now_slot, now_slot.wrapping_sub(1)] {
+        let mut seed_byte = [payload[0]];
+        xor_with_keystream(shared_key, Section::Seed, 0, slot, &mut seed_byte);
+        let seed = seed_byte[0];
+
+        let mut begin_check = [0xA5u8];
+        xor_with_keystream(shared_key, Section::Begin, seed, slot, &mut begin_check);
+        if begin != begin_check[0] {
+            continue;
+        }
+
+        let mut end_check = [0x5Au8];
+        xor_with_keystream(shared_key, Section::End, seed, slot, &mut end_check);
+        if end != end_check[0] {
+            continue;
+        }
+
+        let mut addr_bytes = data;
+        xor_with_keystream(shared_key, Section::Data, seed, slot, &mut addr_bytes);
+
+        let ip = Ipv4Addr::new(addr_bytes[0], addr_bytes[1], addr_bytes[2], addr_bytes[3]);
+        let port = u16::from_be_bytes([addr_bytes[4], addr_bytes[5]]);
+        return Ok(SocketAddrV4::new(ip, port));
+    }
+
+    Err(MitteError::DescriptionFormatError(String::from(
+        "beacon token expired or invalid",
+    )))
+}
+
+/// Encode arbitrary bytes as a base62 string (big-endian, most significant
+/// digit first).
+fn base62_encode(bytes: &[u8]) -> String {
+    let mut digits: Vec<u8> = vec![0];
+
+    for &byte in bytes {
+        let mut carry = byte as u32;
+        for d in digits.iter_mut() {
+            let val = (*d as u32) * 256 + carry;
+            *d = (val % 62) as u8;
+            carry = val / 62;
+        }
+        while carry > 0 {
+            digits.push((carry % 62) as u8);
+            carry /= 62;
+        }
+    }
+
+    digits.iter().rev().map(|&d| ALPHABET[d as usize] as char).collect()
+}
+
+/// Inverse of [`base62_encode`], zero-padded on the left to exactly
+/// `out_len` bytes.
+fn base62_decode(s: &str, out_len: usize) -> Result<Vec<u8>, MitteError> {
+    let mut value: Vec<u8> = vec![0];
+
+    for ch in s.chars() {
+        let digit = ALPHABET
+            .iter()
+            .position(|&c| c as char == ch)
+            .ok_or_else(|| MitteError::DescriptionFormatError(String::from("invalid beacon token character")))?
+            as u32;
+
+        let mut carry = digit;
+        for b in value.iter_mut() {
+            let val = (*b as u32) * 62 + carry;
+            *b = (val & 0xff) as u8;
+            carry = val >> 8;
+        }
+        while carry > 0 {
+            value.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+
+    if value.len() > out_len {
+        return Err(MitteError::DescriptionFormatError(String::from("beacon token too large")));
+    }
+
+    value.resize(out_len, 0);
+    value.reverse();
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a token the same way [`encode`] does, but for an arbitrary
+    /// slot instead of whatever `SystemTime::now()` falls into, so the
+    /// current/previous-slot boundary can be tested without depending on
+    /// wall-clock timing.
+    fn token_for_slot(addr: &SocketAddrV4, shared_key: &[u8], slot: u16, seed: u8) -> String {
+        let mut payload = [0u8; PAYLOAD_LEN];
+
+        let mut seed_byte = [seed];
+        xor_with_keystream(shared_key, Section::Seed, 0, slot, &mut seed_byte);
+        payload[0] = seed_byte[0];
+
+        let mut begin = [0xA5u8];
+        xor_with_keystream(shared_key, Section::Begin, seed, slot, &mut begin);
+        payload[1] = begin[0];
+
+        let mut data = [0u8; 6];
+        data[..4].copy_from_slice(&addr.ip().octets());
+        data[4..].copy_from_slice(&addr.port().to_be_bytes());
+        xor_with_keystream(shared_key, Section::Data, seed, slot, &mut data);
+        payload[2..8].copy_from_slice(&data);
+
+        let mut end = [0x5Au8];
+        xor_with_keystream(shared_key, Section::End, seed, slot, &mut end);
+        payload[8] = end[0];
+
+        base62_encode(&payload)
+    }
+
+    #[test]
+    fn base62_round_trips_arbitrary_bytes() {
+        let cases: [&[u8]; 4] = [
+            &[0; 9],
+            &[255; 9],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 1],
+        ];
+        for bytes in cases {
+            let encoded = base62_encode(bytes);
+            let decoded = base62_decode(&encoded, bytes.len()).unwrap();
+            assert_eq!(decoded, bytes);
+        }
+    }
+
+    #[test]
+    fn base62_decode_rejects_invalid_characters() {
+        assert!(base62_decode("not-valid!", PAYLOAD_LEN).is_err());
+    }
+
+    #[test]
+    fn encode_parse_round_trips_an_address() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 42), 9521);
+        let key = b"shared secret";
+        let token = encode(&addr, key);
+        assert_eq!(parse(&token, key).unwrap(), addr);
+    }
+
+    #[test]
+    fn parse_accepts_current_and_previous_slot_but_not_older() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1234);
+        let key = b"shared secret";
+        let now_slot = time_slot(SystemTime::now());
+
+        let current = token_for_slot(&addr, key, now_slot, 7);
+        let previous = token_for_slot(&addr, key, now_slot.wrapping_sub(1), 7);
+        let stale = token_for_slot(&addr, key, now_slot.wrapping_sub(2), 7);
+
+        assert_eq!(parse(&current, key).unwrap(), addr);
+        assert_eq!(parse(&previous, key).unwrap(), addr);
+        assert!(parse(&stale, key).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_wrong_key() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1234);
+        let token = encode(&addr, b"key-a");
+        assert!(parse(&token, b"key-b").is_err());
+    }
+}
@@ -7,7 +7,10 @@ use std::fmt;
 pub enum MitteError  {
     DescriptionFormatError(String),
     AgentCreationError(String),
-    HandshakeError(String)
+    HandshakeError(String),
+    ListenError(String),
+    SendError(String),
+    ReceiveError(String)
 }
 
 impl fmt::Display for MitteError {
@@ -0,0 +1,222 @@
+//! Datagram fragmentation and reassembly.
+//!
+//! A single UDP datagram can't always carry a whole message (IP-level
+//! fragmentation is unreliable to depend on, and we'd like to keep clear of
+//! it anyway), so [`fragment`] splits an encrypted frame into chunks small
+//! enough to send as individual datagrams, each carrying a message id,
+//! fragment index, and fragment count. [`Reassembler`] buffers fragments
+//! per message id, tolerates them arriving out of order, and returns the
+//! reassembled frame once every fragment is in.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+/// Largest chunk of a frame carried per datagram. Conservative enough to
+/// stay clear of IP-level fragmentation on typical MTUs.
+pub const MAX_FRAGMENT_PAYLOAD: usize = 1200;
+
+/// Header size in bytes: a 4-byte message id, 2-byte fragment index, and
+/// 2-byte fragment count.
+const HEADER_LEN: usize = 8;
+
+/// Largest datagram a fragment can occupy, header included; sized so
+/// callers know how big to make their receive buffer.
+pub const MAX_FRAGMENT_LEN: usize = HEADER_LEN + MAX_FRAGMENT_PAYLOAD;
+
+/// How long a partially-received message is kept around waiting on its
+/// remaining fragments before being dropped, so a lost fragment can't leak
+/// memory indefinitely.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most reassembly buffers kept in flight at once, so a peer that
+/// floods fragments for messages it never completes can't grow this table
+/// without bound.
+pub const MAX_IN_FLIGHT: usize = 64;
+
+/// Split `frame` into one or more fragments, each prefixed with
+/// `[message_id (4 bytes) | index (2 bytes) | total (2 bytes)]`.
+pub fn fragment(message_id: u32, frame: &[u8]) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = if frame.is_empty() {
+        vec![frame]
+    } else {
+        frame.chunks(MAX_FRAGMENT_PAYLOAD).collect()
+    };
+
+    let total = chunks.len() as u16;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut out = Vec::with_capacity(HEADER_LEN + chunk.len());
+            out.extend_from_slice(&message_id.to_be_bytes());
+            out.extend_from_slice(&(i as u16).to_be_bytes());
+            out.extend_from_slice(&total.to_be_bytes());
+            out.extend_from_slice(chunk);
+            out
+        })
+        .collect()
+}
+
+/// The fragments collected so far for one message id.
+#[derive(Debug)]
+struct PartialMessage {
+    total: u16,
+    chunks: HashMap<u16, Vec<u8>>,
+    first_seen: Instant,
+}
+
+/// Buffers fragments per message id until every fragment for that id has
+/// arrived.
+#[derive(Debug)]
+pub struct Reassembler {
+    partial: HashMap<u32, PartialMessage>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler { partial: HashMap::new() }
+    }
+
+    /// Feed one received datagram in. Returns the reassembled frame once
+    /// every fragment for its message id has arrived, `None` if the
+    /// message is still incomplete (or the datagram was malformed, or
+    /// dropped for being past the in-flight cap).
+    pub fn insert(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        self.evict_stale();
+
+        if datagram.len() < HEADER_LEN {
+            return None;
+        }
+
+        let message_id = u32::from_be_bytes(datagram[0..4].try_into().unwrap());
+        let index = u16::from_be_bytes(datagram[4..6].try_into().unwrap());
+        let total = u16::from_be_bytes(datagram[6..8].try_into().unwrap());
+        let chunk = datagram[HEADER_LEN..].to_vec();
+
+        if total == 0 || index >= total {
+            return None;
+        }
+
+        if !self.partial.contains_key(&message_id) && self.partial.len() >= MAX_IN_FLIGHT {
+            // Don't let a peer flooding BEGIN fragments for messages it
+            // never completes grow this table without bound; drop the new
+            // message rather than evicting one already in progress.
+            return None;
+        }
+
+        let entry = self.partial.entry(message_id).or_insert_with(|| PartialMessage {
+            total,
+            chunks: HashMap::new(),
+            first_seen: Instant::now(),
+        });
+        entry.chunks.insert(index, chunk);
+
+        if entry.chunks.len() as u16 != entry.total {
+            return None;
+        }
+
+        let entry = self.partial.remove(&message_id).unwrap();
+        let mut frame = Vec::new();
+        for i in 0..entry.total {
+            frame.extend_from_slice(entry.chunks.get(&i)?);
+        }
+        Some(frame)
+    }
+
+    /// Drop any message whose first fragment arrived longer than
+    /// [`DEFAULT_REASSEMBLY_TIMEOUT`] ago and that still isn't complete.
+    fn evict_stale(&mut self) {
+        let now = Instant::now();
+        self.partial
+            .retain(|_, m| now.duration_since(m.first_seen) < DEFAULT_REASSEMBLY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassembles_fragments_received_out_of_order() {
+        // Big enough to span several fragments, so reassembly actually has
+        // ordering to get right.
+        let frame: Vec<u8> = (0..(MAX_FRAGMENT_PAYLOAD * 3)).map(|i| i as u8).collect();
+        let frags = fragment(42, &frame);
+        assert!(frags.len() > 1);
+
+        let mut reassembler = Reassembler::new();
+        for frag in frags.iter().rev() {
+            if let Some(out) = reassembler.insert(frag) {
+                assert_eq!(out, frame);
+                return;
+            }
+        }
+        panic!("reassembler never completed the message");
+    }
+
+    #[test]
+    fn a_duplicate_fragment_does_not_complete_the_message_early() {
+        let frame = vec![1u8, 2, 3, 4, 5];
+
+        // Build a two-fragment message by hand, under one message id, so we
+        // can insert one of its fragments twice.
+        let mut first = Vec::new();
+        first.extend_from_slice(&1u32.to_be_bytes());
+        first.extend_from_slice(&0u16.to_be_bytes());
+        first.extend_from_slice(&2u16.to_be_bytes());
+        first.extend_from_slice(&frame[..2]);
+
+        let mut second = Vec::new();
+        second.extend_from_slice(&1u32.to_be_bytes());
+        second.extend_from_slice(&1u16.to_be_bytes());
+        second.extend_from_slice(&2u16.to_be_bytes());
+        second.extend_from_slice(&frame[2..]);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.insert(&first), None);
+        assert_eq!(reassembler.insert(&first), None); // duplicate, still incomplete
+        assert_eq!(reassembler.insert(&second), Some(frame));
+    }
+
+    #[test]
+    fn max_in_flight_caps_distinct_in_progress_messages() {
+        let mut reassembler = Reassembler::new();
+
+        // Fill the table with MAX_IN_FLIGHT distinct, never-completed
+        // two-fragment messages (only their first fragment each).
+        for id in 0..MAX_IN_FLIGHT as u32 {
+            let mut first = Vec::new();
+            first.extend_from_slice(&id.to_be_bytes());
+            first.extend_from_slice(&0u16.to_be_bytes());
+            first.extend_from_slice(&2u16.to_be_bytes());
+            first.push(0);
+            assert_eq!(reassembler.insert(&first), None);
+        }
+
+        // One more distinct message id should be dropped outright rather
+        // than evicting one already in progress...
+        let overflow_id = MAX_IN_FLIGHT as u32;
+        let mut overflow_first = Vec::new();
+        overflow_first.extend_from_slice(&overflow_id.to_be_bytes());
+        overflow_first.extend_from_slice(&0u16.to_be_bytes());
+        overflow_first.extend_from_slice(&2u16.to_be_bytes());
+        overflow_first.push(0);
+        assert_eq!(reassembler.insert(&overflow_first), None);
+
+        // ...so even its remaining fragment can never complete it.
+        let mut overflow_second = Vec::new();
+        overflow_second.extend_from_slice(&overflow_id.to_be_bytes());
+        overflow_second.extend_from_slice(&1u16.to_be_bytes());
+        overflow_second.extend_from_slice(&2u16.to_be_bytes());
+        overflow_second.push(1);
+        assert_eq!(reassembler.insert(&overflow_second), None);
+    }
+
+    #[test]
+    fn insert_rejects_a_datagram_shorter_than_the_header() {
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.insert(&[0u8; 3]), None);
+    }
+}
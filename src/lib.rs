@@ -11,9 +11,29 @@ pub mod error;
 // Agent information: i.e. most of the library
 mod agent;
 
+// Ephemeral session establishment and rekeying, used internally by `agent`
+mod session;
+
+// The peer table: liveness timeouts, address tracking, reconnect backoff
+mod peers;
+
+// Datagram fragmentation and reassembly for messages bigger than one datagram
+mod fragment;
+
+// Time-rotating address tokens for out-of-band peer discovery
+pub mod beacon;
+
 // We publish every public function in agents
 pub use agent::*;
 
+// `Agent::set_rekey_policy` takes a `RekeyPolicy`, so it needs to be
+// nameable outside the crate even though `session` itself stays private.
+pub use session::RekeyPolicy;
+
+// Fixtures shared by the unit tests in the other modules.
+#[cfg(test)]
+mod test_support;
+
 // As most of the actual code is in [`agent`], we will
 // leverage this module to write usage examples and
 // unit tests.
@@ -0,0 +1,288 @@
+//! A peer table indexed for the three ways [`crate::agent::Agent`] needs to
+//! look a peer up, with liveness timeouts, address-change tracking, and
+//! reconnect backoff.
+//!
+//! This replaces the `Vec<AgentDescription>` the agent used to carry, whose
+//! every update was an O(n) clone-filter-rebuild over the whole list.
+
+use super::agent::AgentDescription;
+use super::session::Session;
+
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// How long a peer may go unseen before [`PeerList::maintenance`] evicts it,
+/// unless a different timeout was given to [`PeerList::upsert`].
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Starting point for reconnect backoff; doubles on every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Ceiling on reconnect backoff, so a peer that's been gone for a long time
+/// doesn't end up with an ever-growing wait between attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Everything the table tracks about a peer beyond its description: the
+/// negotiated session (if any), when it was last heard from, its liveness
+/// timeout, and where it stands in the reconnect backoff schedule.
+#[derive(Debug)]
+struct PeerRecord {
+    desc: AgentDescription,
+    session: Option<Session>,
+    last_seen: Instant,
+    timeout: Duration,
+    address_changed: bool,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+/// A table of known peers, indexed by name (the identity), by the address
+/// an inbound datagram arrived from (so it can be attributed to a peer),
+/// and from identity back to current address (so an address change is a
+/// single map update instead of a clone-filter-rebuild over everything).
+#[derive(Debug)]
+pub struct PeerList {
+    by_name: HashMap<String, PeerRecord>,
+    addr_to_name: HashMap<SocketAddrV4, String>,
+    name_to_addr: HashMap<String, SocketAddrV4>,
+}
+
+impl PeerList {
+    pub fn new() -> Self {
+        PeerList {
+            by_name: HashMap::new(),
+            addr_to_name: HashMap::new(),
+            name_to_addr: HashMap::new(),
+        }
+    }
+
+    /// Whether `desc` is already in the table (compared the same way
+    /// `AgentDescription`'s `PartialEq` does: by name and key).
+    pub fn contains(&self, desc: &AgentDescription) -> bool {
+        self.by_name.get(&desc.name).is_some_and(|r| &r.desc == desc)
+    }
+
+    /// Insert a new peer, or update an existing one's description. If the
+    /// peer's address changed, `reconnect()` will pick it up on the next
+    /// call. Resets the liveness clock either way.
+    pub fn upsert(&mut self, desc: AgentDescription, timeout: Duration) {
+        let now = Instant::now();
+
+        if let Some(old_addr) = self.name_to_addr.get(&desc.name) {
+            if Some(*old_addr) != desc.addr {
+                self.addr_to_name.remove(old_addr);
+            }
+        }
+        if let Some(addr) = desc.addr {
+            self.addr_to_name.insert(addr, desc.name.clone());
+            self.name_to_addr.insert(desc.name.clone(), addr);
+        }
+
+        let address_changed = self
+            .by_name
+            .get(&desc.name)
+            .is_some_and(|r| r.desc.addr.is_some() && r.desc.addr != desc.addr);
+
+        match self.by_name.get_mut(&desc.name) {
+            Some(record) => {
+                record.desc = desc;
+                record.last_seen = now;
+                record.timeout = timeout;
+                record.address_changed = address_changed;
+            }
+            None => {
+                self.by_name.insert(
+                    desc.name.clone(),
+                    PeerRecord {
+                        desc,
+                        session: None,
+                        last_seen: now,
+                        timeout,
+                        address_changed: false,
+                        backoff: INITIAL_BACKOFF,
+                        next_attempt: now,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Look up a peer's description by name.
+    pub fn get(&self, name: &str) -> Option<&AgentDescription> {
+        self.by_name.get(name).map(|r| &r.desc)
+    }
+
+    /// Attribute an inbound datagram's source address to a known peer.
+    pub fn attribute(&self, addr: &SocketAddrV4) -> Option<&AgentDescription> {
+        self.addr_to_name.get(addr).and_then(|name| self.get(name))
+    }
+
+    /// Mark a peer as heard from just now, resetting its liveness clock.
+    pub fn touch(&mut self, name: &str) {
+        if let Some(record) = self.by_name.get_mut(name) {
+            record.last_seen = Instant::now();
+        }
+    }
+
+    /// The negotiated session for a peer, if any.
+    pub fn session(&self, name: &str) -> Option<&Session> {
+        self.by_name.get(name).and_then(|r| r.session.as_ref())
+    }
+
+    /// Install a freshly negotiated session for a peer.
+    pub fn set_session(&mut self, name: &str, session: Session) {
+        if let Some(record) = self.by_name.get_mut(name) {
+            record.session = Some(session);
+        }
+    }
+
+    /// Record that a message was just sent to this peer's session.
+    pub fn note_message_sent(&mut self, name: &str) {
+        if let Some(record) = self.by_name.get_mut(name) {
+            if let Some(session) = &mut record.session {
+                session.note_message_sent();
+            }
+        }
+    }
+
+    /// Evict every peer that hasn't been seen within its timeout, returning
+    /// the descriptions of whoever got evicted so the caller can log or
+    /// otherwise react to it.
+    pub fn maintenance(&mut self) -> Vec<AgentDescription> {
+        let now = Instant::now();
+        let stale: Vec<String> = self
+            .by_name
+            .iter()
+            .filter(|(_, r)| now.duration_since(r.last_seen) >= r.timeout)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        let mut evicted = Vec::with_capacity(stale.len());
+        for name in stale {
+            if let Some(record) = self.by_name.remove(&name) {
+                if let Some(addr) = record.desc.addr {
+                    self.addr_to_name.remove(&addr);
+                }
+                self.name_to_addr.remove(&name);
+                evicted.push(record.desc);
+            }
+        }
+        evicted
+    }
+
+    /// Peers whose address has changed since we last handshaked with them
+    /// and whose backoff has elapsed, i.e. who are due for
+    /// `Agent::reconnect` to re-handshake.
+    pub fn due_for_reconnect(&self) -> Vec<AgentDescription> {
+        let now = Instant::now();
+        self.by_name
+            .values()
+            .filter(|r| r.address_changed && now >= r.next_attempt)
+            .map(|r| r.desc.clone())
+            .collect()
+    }
+
+    /// Record a reconnect attempt's outcome, doubling the backoff (capped)
+    /// on failure or clearing it on success.
+    pub fn record_reconnect_attempt(&mut self, name: &str, succeeded: bool) {
+        if let Some(record) = self.by_name.get_mut(name) {
+            if succeeded {
+                record.address_changed = false;
+                record.backoff = INITIAL_BACKOFF;
+            } else {
+                record.backoff = (record.backoff * 2).min(MAX_BACKOFF);
+            }
+            record.next_attempt = Instant::now() + record.backoff;
+        }
+    }
+
+    /// Every live peer along with when it was last heard from.
+    pub fn live_peers(&self) -> Vec<(AgentDescription, Instant)> {
+        let now = Instant::now();
+        self.by_name
+            .values()
+            .filter(|r| now.duration_since(r.last_seen) < r.timeout)
+            .map(|r| (r.desc.clone(), r.last_seen))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::small_rsa_keypair;
+
+    fn desc(addr: &str, name: &str) -> AgentDescription {
+        let (_, pub_key) = small_rsa_keypair();
+        let key_bytes = bincode::serialize(&pub_key).unwrap();
+        AgentDescription::new(addr, name, &key_bytes).unwrap()
+    }
+
+    #[test]
+    fn upsert_inserts_then_updates_in_place() {
+        let mut peers = PeerList::new();
+        let alice = desc("127.0.0.1:9000", "alice");
+        assert!(!peers.contains(&alice));
+
+        peers.upsert(alice.clone(), DEFAULT_TIMEOUT);
+        assert!(peers.contains(&alice));
+        assert_eq!(peers.get("alice").unwrap().name, "alice");
+
+        let addr: SocketAddrV4 = "127.0.0.1:9000".parse().unwrap();
+        assert_eq!(peers.attribute(&addr).unwrap().name, "alice");
+    }
+
+    #[test]
+    fn upsert_with_changed_address_flags_due_for_reconnect() {
+        let mut peers = PeerList::new();
+        let bob = desc("127.0.0.1:9001", "bob");
+        peers.upsert(bob.clone(), DEFAULT_TIMEOUT);
+        assert!(peers.due_for_reconnect().is_empty());
+
+        let mut moved = bob;
+        moved.addr = Some("127.0.0.1:9002".parse().unwrap());
+        peers.upsert(moved, DEFAULT_TIMEOUT);
+
+        let due = peers.due_for_reconnect();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].name, "bob");
+    }
+
+    #[test]
+    fn maintenance_evicts_peers_past_their_timeout() {
+        let mut peers = PeerList::new();
+        let carol = desc("127.0.0.1:9003", "carol");
+        peers.upsert(carol, Duration::from_millis(0));
+
+        let evicted = peers.maintenance();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].name, "carol");
+        assert!(peers.get("carol").is_none());
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_on_failure_and_resets_on_success() {
+        let mut peers = PeerList::new();
+        let dave = desc("127.0.0.1:9004", "dave");
+        peers.upsert(dave.clone(), DEFAULT_TIMEOUT);
+
+        let mut moved = dave;
+        moved.addr = Some("127.0.0.1:9005".parse().unwrap());
+        peers.upsert(moved, DEFAULT_TIMEOUT);
+        assert_eq!(peers.due_for_reconnect().len(), 1);
+
+        // A failed attempt backs off into the future, so the peer drops out
+        // of the due set until that backoff elapses.
+        peers.record_reconnect_attempt("dave", false);
+        assert!(peers.due_for_reconnect().is_empty());
+        assert_eq!(peers.by_name["dave"].backoff, INITIAL_BACKOFF * 2);
+
+        // A successful attempt clears the address-changed flag, so the peer
+        // isn't considered due again even once the backoff would've elapsed.
+        peers.record_reconnect_attempt("dave", true);
+        assert!(peers.due_for_reconnect().is_empty());
+        assert_eq!(peers.by_name["dave"].backoff, INITIAL_BACKOFF);
+    }
+}
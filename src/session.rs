@@ -0,0 +1,524 @@
+//! Ephemeral Diffie-Hellman session establishment and rekeying.
+//!
+//! This module runs a small Noise-inspired handshake (`e`, `<-e,ee`,
+//! `confirm`) on top of the identity handshake in [`crate::agent`]. Each
+//! side generates a fresh X25519 keypair for the session, authenticates its
+//! ephemeral public key by signing it with the long-lived RSA identity key
+//! already carried in `AgentDescription`, and both sides feed the resulting
+//! shared secret through HKDF to derive independent send/receive keys.
+//!
+//! Because the transport is lossy UDP, every message carries an explicit
+//! [`HandshakeStep`] tag so a dropped or reordered datagram can be
+//! retransmitted without corrupting either side's state. A session is only
+//! ever created or replaced by a handshake run to completion; a replayed or
+//! out-of-context handshake message arriving after a session is established
+//! is simply ignored by the caller rather than tearing anything down.
+
+use super::error::*;
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use rand::rngs::OsRng;
+use rsa::{Hash, PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+
+use x25519_dalek::{EphemeralSecret, PublicKey as XPublicKey};
+
+/// Tags the step a handshake datagram belongs to, so a peer can tell a
+/// retransmit of a step it already processed from the next step in the
+/// dance.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeStep {
+    /// `e`: initiator sends its ephemeral public key, signed by its identity key
+    InitEphemeral,
+    /// `<-e, ee`: responder sends its ephemeral key and the DH has now run
+    RespEphemeral,
+    /// `confirm`: initiator acknowledges the derived session is in place
+    Confirm,
+}
+
+/// A single handshake datagram: a step tag, a 32-byte payload, and a
+/// signature over that payload made with the sender's long-lived RSA
+/// identity key. What the payload *is* depends on `step`: for
+/// [`HandshakeStep::InitEphemeral`]/[`HandshakeStep::RespEphemeral`] it's
+/// the sender's ephemeral X25519 public key (so a peer can't be tricked
+/// into deriving a session with an ephemeral key nobody vouched for); for
+/// [`HandshakeStep::Confirm`] it's the [`transcript_hash`] binding the
+/// confirmation to this specific handshake run rather than a real key.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct HandshakeMessage {
+    step: HandshakeStep,
+    payload: [u8; 32],
+    signature: Vec<u8>,
+}
+
+/// Leading byte [`crate::agent::Agent::send_message`]/[`recv_message`]
+/// prefix a datagram with so the two traffic kinds can share a socket: a
+/// data frame the caller asked to send, or one of these handshake steps
+/// arriving in-band because a session is due for a rekey. Without this tag
+/// a mid-conversation rekey's `e` would just look like a malformed
+/// fragment to whichever side is blocked waiting on the next message.
+pub(crate) const FRAME_HANDSHAKE: u8 = 1;
+
+/// How often an established [`Session`] should be renegotiated.
+///
+/// Rekeying happens transparently on the next `send_message` once either
+/// threshold is crossed, whichever comes first. Configured per-agent via
+/// [`crate::agent::Agent::set_rekey_policy`]; [`run_initiator`] and
+/// [`run_responder`]/[`complete_responder`] take the policy to apply to the
+/// session they establish rather than always falling back to [`Default`].
+#[derive(Debug, Clone)]
+pub struct RekeyPolicy {
+    pub max_messages: u64,
+    pub max_age: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_messages: 1000,
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Floor under `max_messages`/`max_age`: anything lower would make
+/// [`Session::needs_rekey`] true again immediately after the rekey it just
+/// triggered completes, turning every `send_message`/`recv_message` call
+/// into a full handshake round-trip instead of an occasional renegotiation.
+/// `max_messages` floors at 2, not 1: a session starts at `messages_sent ==
+/// 0`, so a floor of 1 would still make the very next call after a rekey
+/// trip `needs_rekey` again.
+const MIN_MAX_MESSAGES: u64 = 2;
+const MIN_MAX_AGE: Duration = Duration::from_secs(1);
+
+impl RekeyPolicy {
+    /// Clamp `max_messages`/`max_age` up to [`MIN_MAX_MESSAGES`]/[`MIN_MAX_AGE`],
+    /// so a degenerate policy (e.g. `max_messages: 0`) can't cause a rekey
+    /// storm. Applied by [`crate::agent::Agent::set_rekey_policy`].
+    pub(crate) fn clamped(mut self) -> Self {
+        self.max_messages = self.max_messages.max(MIN_MAX_MESSAGES);
+        self.max_age = self.max_age.max(MIN_MAX_AGE);
+        self
+    }
+}
+
+/// The live symmetric state for a single peer, derived from an ephemeral
+/// X25519 exchange. `send_key`/`recv_key` are directional: each side derives
+/// them the same way, but swapped, so the initiator's send key is the
+/// responder's recv key and vice versa.
+#[derive(Debug)]
+pub struct Session {
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    messages_sent: u64,
+    established_at: Instant,
+    policy: RekeyPolicy,
+}
+
+impl Session {
+    /// Whether this session has sent enough messages, or lived long enough,
+    /// that it should be renegotiated before the next message goes out.
+    pub fn needs_rekey(&self) -> bool {
+        self.messages_sent >= self.policy.max_messages
+            || self.established_at.elapsed() >= self.policy.max_age
+    }
+
+    /// The key this side should encrypt outgoing messages under.
+    pub fn send_key(&self) -> &[u8; 32] {
+        &self.send_key
+    }
+
+    /// The key this side should decrypt incoming messages with.
+    pub fn recv_key(&self) -> &[u8; 32] {
+        &self.recv_key
+    }
+
+    pub fn note_message_sent(&mut self) {
+        self.messages_sent += 1;
+    }
+}
+
+/// Sign `ephemeral_pub` with `identity`, returning a ready-to-send
+/// [`HandshakeMessage`] for the given step.
+fn sign_step(
+    step: HandshakeStep,
+    ephemeral_pub: &XPublicKey,
+    identity: &RsaPrivateKey,
+) -> Result<HandshakeMessage, MitteError> {
+    let digest = Sha256::digest(ephemeral_pub.as_bytes());
+    let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+
+    let signature = identity
+        .sign(padding, &digest)
+        .map_err(|_| MitteError::HandshakeError(String::from("cannot sign ephemeral key")))?;
+
+    Ok(HandshakeMessage {
+        step,
+        payload: *ephemeral_pub.as_bytes(),
+        signature,
+    })
+}
+
+/// Verify that `msg` was signed by `peer_key`, and that it carries the step
+/// we expected to see next.
+fn verify_step(
+    msg: &HandshakeMessage,
+    expected: HandshakeStep,
+    peer_key: &RsaPublicKey,
+) -> Result<XPublicKey, MitteError> {
+    if msg.step != expected {
+        return Err(MitteError::HandshakeError(String::from(
+            "handshake step out of order",
+        )));
+    }
+
+    let digest = Sha256::digest(msg.payload);
+    let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+
+    peer_key
+        .verify(padding, &digest, &msg.signature)
+        .map_err(|_| MitteError::HandshakeError(String::from("ephemeral key signature invalid")))?;
+
+    Ok(XPublicKey::from(msg.payload))
+}
+
+/// A hash binding a `confirm` step to the exact pair of ephemeral keys this
+/// handshake just exchanged, in a fixed initiator-then-responder order both
+/// sides can recompute independently. Signing this (rather than, say, the
+/// initiator's ephemeral key again) is what stops a captured `InitEphemeral`
+/// from being replayed and completed with a confirm forged for a different
+/// run of the handshake: the transcript hash only ever matches once, for the
+/// specific responder ephemeral key generated in response to it.
+fn transcript_hash(initiator_pub: &XPublicKey, responder_pub: &XPublicKey) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(initiator_pub.as_bytes());
+    hasher.update(responder_pub.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Sign a transcript hash as the `confirm` step, carried in the message's
+/// `payload` field -- see [`HandshakeMessage`] for why that field means a
+/// transcript hash rather than an ephemeral key on this step.
+fn sign_confirm(transcript: [u8; 32], identity: &RsaPrivateKey) -> Result<HandshakeMessage, MitteError> {
+    let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+    let signature = identity
+        .sign(padding, &transcript)
+        .map_err(|_| MitteError::HandshakeError(String::from("cannot sign handshake confirmation")))?;
+
+    Ok(HandshakeMessage {
+        step: HandshakeStep::Confirm,
+        payload: transcript,
+        signature,
+    })
+}
+
+/// Verify that `msg` is a `confirm` step, signed by `peer_key`, over exactly
+/// `expected_transcript` -- not merely any validly-signed confirm, which is
+/// what let a replayed `InitEphemeral` be paired with an attacker-forged
+/// confirm before this check existed.
+fn verify_confirm(
+    msg: &HandshakeMessage,
+    expected_transcript: &[u8; 32],
+    peer_key: &RsaPublicKey,
+) -> Result<(), MitteError> {
+    if msg.step != HandshakeStep::Confirm {
+        return Err(MitteError::HandshakeError(String::from(
+            "handshake step out of order",
+        )));
+    }
+
+    if &msg.payload != expected_transcript {
+        return Err(MitteError::HandshakeError(String::from(
+            "handshake confirmation does not match this session",
+        )));
+    }
+
+    let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+    peer_key
+        .verify(padding, expected_transcript, &msg.signature)
+        .map_err(|_| MitteError::HandshakeError(String::from("handshake confirmation signature invalid")))?;
+
+    Ok(())
+}
+
+/// Derive directional send/recv keys from a completed DH exchange. The two
+/// labels, used as HKDF `info`, distinguish initiator-to-responder traffic from responder-to-initiator
+/// traffic so the two sides never accidentally share a key in both
+/// directions.
+fn derive_keys(shared: &[u8; 32], our_label: &[u8], their_label: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(None, shared);
+
+    let mut send_key = [0u8; 32];
+    let mut recv_key = [0u8; 32];
+
+    hk.expand(our_label, &mut send_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    hk.expand(their_label, &mut recv_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    (send_key, recv_key)
+}
+
+fn send_handshake(socket: &UdpSocket, msg: &HandshakeMessage) -> Result<(), MitteError> {
+    let mut bytes = vec![FRAME_HANDSHAKE];
+    bytes.extend(
+        bincode::serialize(msg)
+            .map_err(|_| MitteError::HandshakeError(String::from("cannot serialize handshake step")))?,
+    );
+    socket
+        .send(&bytes)
+        .map_err(|_| MitteError::HandshakeError(String::from("peer disconnected")))?;
+    Ok(())
+}
+
+fn recv_handshake(socket: &UdpSocket) -> Result<HandshakeMessage, MitteError> {
+    let mut buf = [0u8; 513];
+    let n = socket
+        .recv(&mut buf)
+        .map_err(|_| MitteError::HandshakeError(String::from("handshake unacknowledged")))?;
+    parse_handshake_frame(&buf[..n])
+}
+
+/// Decode a datagram tagged [`FRAME_HANDSHAKE`] (leading byte included; it is
+/// checked and stripped here) into a [`HandshakeMessage`].
+/// Exposed so `recv_message` can hand off a datagram it received itself
+/// (because it arrived interleaved with ordinary traffic) without having to
+/// read another one through [`recv_handshake`].
+pub(crate) fn parse_handshake_frame(datagram: &[u8]) -> Result<HandshakeMessage, MitteError> {
+    if datagram.first() != Some(&FRAME_HANDSHAKE) {
+        return Err(MitteError::HandshakeError(String::from("not a handshake frame")));
+    }
+    bincode::deserialize(&datagram[1..])
+        .map_err(|_| MitteError::HandshakeError(String::from("malformed handshake step")))
+}
+
+/// Run the initiator side of the ephemeral handshake: `e`, then wait for
+/// `<-e,ee`, then send `confirm`. `socket` must already be connected to the
+/// peer. `policy` governs when the returned [`Session`] will itself need
+/// rekeying. Returns the freshly established [`Session`].
+pub fn run_initiator(
+    socket: &UdpSocket,
+    identity: &RsaPrivateKey,
+    peer_key: &RsaPublicKey,
+    policy: RekeyPolicy,
+) -> Result<Session, MitteError> {
+    let my_secret = EphemeralSecret::random_from_rng(OsRng);
+    let my_public = XPublicKey::from(&my_secret);
+
+    send_handshake(socket, &sign_step(HandshakeStep::InitEphemeral, &my_public, identity)?)?;
+
+    // A dropped `e` just looks like a dropped ack to the caller; retry once
+    // before giving up, since `recv_handshake` already carries the socket's
+    // one-second timeout set by `Agent::handshake`.
+    let resp = match recv_handshake(socket) {
+        Ok(m) => m,
+        Err(_) => {
+            send_handshake(socket, &sign_step(HandshakeStep::InitEphemeral, &my_public, identity)?)?;
+            recv_handshake(socket)?
+        }
+    };
+
+    let their_public = verify_step(&resp, HandshakeStep::RespEphemeral, peer_key)?;
+    let shared = my_secret.diffie_hellman(&their_public);
+    let (send_key, recv_key) = derive_keys(shared.as_bytes(), b"mitte init->resp", b"mitte resp->init");
+
+    // `confirm` is signed over a hash of both ephemeral keys exchanged in
+    // this run, not just our own key again, so a confirm from one handshake
+    // can never be mistaken for -- or forged against -- another: an attacker
+    // who replays a captured `InitEphemeral` still can't produce a valid
+    // confirm, since that requires signing a transcript that depends on the
+    // responder's fresh ephemeral key.
+    let transcript = transcript_hash(&my_public, &their_public);
+    send_handshake(socket, &sign_confirm(transcript, identity)?)?;
+
+    Ok(Session {
+        send_key,
+        recv_key,
+        messages_sent: 0,
+        established_at: Instant::now(),
+        policy,
+    })
+}
+
+/// Run the responder side of the ephemeral handshake: wait for `e`, send
+/// `<-e,ee`, then wait for `confirm`. `socket` must already be connected to
+/// the peer, since every step goes through `send`/`recv`.
+/// `policy` governs when the returned [`Session`] will itself need
+/// rekeying. Returns the freshly established [`Session`].
+pub fn run_responder(
+    socket: &UdpSocket,
+    identity: &RsaPrivateKey,
+    peer_key: &RsaPublicKey,
+    policy: RekeyPolicy,
+) -> Result<Session, MitteError> {
+    let init = recv_handshake(socket)?;
+    complete_responder(socket, identity, peer_key, init, policy)
+}
+
+/// The rest of [`run_responder`], for a caller that already has the `e` step
+/// in hand (`recv_message`, dispatching an in-band rekey request it read off
+/// the socket itself) rather than needing to receive it here.
+pub(crate) fn complete_responder(
+    socket: &UdpSocket,
+    identity: &RsaPrivateKey,
+    peer_key: &RsaPublicKey,
+    init: HandshakeMessage,
+    policy: RekeyPolicy,
+) -> Result<Session, MitteError> {
+    let their_public = verify_step(&init, HandshakeStep::InitEphemeral, peer_key)?;
+
+    let my_secret = EphemeralSecret::random_from_rng(OsRng);
+    let my_public = XPublicKey::from(&my_secret);
+
+    send_handshake(socket, &sign_step(HandshakeStep::RespEphemeral, &my_public, identity)?)?;
+
+    let shared = my_secret.diffie_hellman(&their_public);
+    let (send_key, recv_key) = derive_keys(shared.as_bytes(), b"mitte resp->init", b"mitte init->resp");
+
+    // The confirm must be signed over the transcript of exactly these two
+    // ephemeral keys (initiator's, then ours), not just carry the Confirm
+    // tag -- otherwise a replayed `e` followed by any validly-tagged confirm
+    // (even one forged for a different run, or signed by a different key
+    // entirely) would be accepted and silently replace whatever session was
+    // already live for this peer.
+    let expected_transcript = transcript_hash(&their_public, &my_public);
+    let confirm = recv_handshake(socket)?;
+    verify_confirm(&confirm, &expected_transcript, peer_key)?;
+
+    Ok(Session {
+        send_key,
+        recv_key,
+        messages_sent: 0,
+        established_at: Instant::now(),
+        policy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use crate::test_support::small_rsa_keypair as keypair;
+
+    /// Two UDP sockets, bound locally and connected to each other, with a
+    /// short read timeout so a test that gets the protocol wrong fails fast
+    /// instead of hanging.
+    fn connected_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        a.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        b.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn handshake_derives_matching_directional_keys() {
+        let (init_sock, resp_sock) = connected_pair();
+        let (init_priv, init_pub) = keypair();
+        let (resp_priv, resp_pub) = keypair();
+
+        let responder = thread::spawn(move || {
+            run_responder(&resp_sock, &resp_priv, &init_pub, RekeyPolicy::default())
+        });
+
+        let initiator_session = run_initiator(&init_sock, &init_priv, &resp_pub, RekeyPolicy::default()).unwrap();
+        let responder_session = responder.join().unwrap().unwrap();
+
+        assert_eq!(initiator_session.send_key(), responder_session.recv_key());
+        assert_eq!(initiator_session.recv_key(), responder_session.send_key());
+    }
+
+    #[test]
+    fn replayed_init_ephemeral_with_forged_confirm_is_rejected() {
+        // Stand in for an attacker who captured one legitimately-signed
+        // InitEphemeral datagram from an earlier, genuine handshake attempt
+        // and replays it later. Since the attacker doesn't hold the real
+        // initiator's private key, it can't sign a confirm bound to the
+        // transcript our responder expects, so the best it can do is send a
+        // garbage one.
+        let (resp_sock, attacker_sock) = connected_pair();
+        let (resp_priv, _resp_pub) = keypair();
+        let (init_priv, init_pub) = keypair();
+
+        let captured_secret = EphemeralSecret::random_from_rng(OsRng);
+        let captured_pub = XPublicKey::from(&captured_secret);
+        let captured_init = sign_step(HandshakeStep::InitEphemeral, &captured_pub, &init_priv).unwrap();
+
+        let responder = thread::spawn(move || {
+            complete_responder(&resp_sock, &resp_priv, &init_pub, captured_init, RekeyPolicy::default())
+        });
+
+        // Drain the RespEphemeral the responder sends back so the attacker
+        // socket isn't left holding an unrelated datagram.
+        let mut buf = [0u8; 513];
+        attacker_sock.recv(&mut buf).unwrap();
+
+        let forged_confirm = HandshakeMessage {
+            step: HandshakeStep::Confirm,
+            payload: [0u8; 32],
+            signature: Vec::new(),
+        };
+        send_handshake(&attacker_sock, &forged_confirm).unwrap();
+
+        assert!(responder.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn needs_rekey_reflects_message_count_and_age_thresholds() {
+        let mut by_count = Session {
+            send_key: [0u8; 32],
+            recv_key: [0u8; 32],
+            messages_sent: 0,
+            established_at: Instant::now(),
+            policy: RekeyPolicy {
+                max_messages: 3,
+                max_age: Duration::from_secs(3600),
+            },
+        };
+        assert!(!by_count.needs_rekey());
+        by_count.messages_sent = 3;
+        assert!(by_count.needs_rekey());
+
+        let by_age = Session {
+            send_key: [0u8; 32],
+            recv_key: [0u8; 32],
+            messages_sent: 0,
+            established_at: Instant::now() - Duration::from_secs(7200),
+            policy: RekeyPolicy {
+                max_messages: 1000,
+                max_age: Duration::from_secs(3600),
+            },
+        };
+        assert!(by_age.needs_rekey());
+    }
+
+    #[test]
+    fn clamped_rejects_degenerate_policies() {
+        let degenerate = RekeyPolicy {
+            max_messages: 0,
+            max_age: Duration::ZERO,
+        }
+        .clamped();
+        assert_eq!(degenerate.max_messages, MIN_MAX_MESSAGES);
+        assert_eq!(degenerate.max_age, MIN_MAX_AGE);
+
+        let sane = RekeyPolicy {
+            max_messages: 500,
+            max_age: Duration::from_secs(300),
+        }
+        .clamped();
+        assert_eq!(sane.max_messages, 500);
+        assert_eq!(sane.max_age, Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,15 @@
+//! Shared fixtures for unit tests across modules.
+
+use rand::rngs::OsRng;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+/// A freshly generated RSA keypair for use as a test identity key.
+///
+/// 512 bits is far too small for real use, but these tests only care about
+/// having *a* key to round-trip through sign/verify or the peer table, not
+/// the key's strength, so a small key keeps them fast.
+pub(crate) fn small_rsa_keypair() -> (RsaPrivateKey, RsaPublicKey) {
+    let priv_key = RsaPrivateKey::new(&mut OsRng, 512).unwrap();
+    let pub_key = RsaPublicKey::from(&priv_key);
+    (priv_key, pub_key)
+}